@@ -0,0 +1,77 @@
+#![no_std]
+#![cfg_attr(test, no_main)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+
+pub mod serial;
+pub mod vga_buffer;
+
+/// テストとして実行できるものを表すトレイト。実行前後にシリアルへ
+/// 名前と結果を出力するので、ホスト側でどのテストが通ったか分かる。
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T> Testable for T
+where
+    T: Fn(),
+{
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+/// `custom_test_frameworks`から呼ばれるテストランナー。各テストを実行し、
+/// 終わったらQEMUを終了させる。診断はすべてシリアル経由で流れる。
+pub fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// テスト中のパニックハンドラ。失敗を報告し、失敗コードでQEMUを終了する。
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/// `isa-debug-exit`デバイスに書き込む終了コード。実際にQEMUへ渡る値は
+/// `(code << 1) | 1`なので、Successは33、Failedは35になる。
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// `isa-debug-exit`デバイス(ポート0xf4)へ書き込んでQEMUを終了させる。
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    unsafe {
+        let code = exit_code as u32;
+        asm!("out dx, eax", in("dx") 0xf4u16, in("eax") code, options(nomem, nostack, preserves_flags));
+    }
+}
+
+// `cargo test --lib`で使うテスト用エントリポイントとパニックハンドラ。
+#[cfg(test)]
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    test_main();
+    loop {}
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info)
+}
@@ -0,0 +1,95 @@
+use core::arch::asm;
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+// COM1のベースアドレス。16550 UARTのレジスタはここからの相対オフセットに並ぶ。
+const COM1_BASE: u16 = 0x3F8;
+
+// バイトを指定したI/Oポートへ書き込む。生の`out`命令を使うので
+// 呼び出し側が正しいポートであることを保証しなければならない。
+unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+// 指定したI/Oポートから1バイト読み込む。
+unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+// 16550互換UARTの最小限のドライバ。ベースアドレスだけを保持し、
+// レジスタへはオフセットでアクセスする。
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    const fn new(base: u16) -> SerialPort {
+        SerialPort { base }
+    }
+
+    // UARTを初期化する。割り込みを無効化し、DLABを立てて38400ボーの分周比を
+    // 設定し、8N1・FIFO有効に戻す。
+    fn init(&mut self) {
+        unsafe {
+            outb(self.base + 1, 0x00); // 割り込みを無効化
+            outb(self.base + 3, 0x80); // DLABを立ててボーレート設定に切り替える
+            outb(self.base, 0x03); // 分周比下位: 115200 / 38400 = 3
+            outb(self.base + 1, 0x00); // 分周比上位
+            outb(self.base + 3, 0x03); // 8ビット・パリティ無し・1ストップビット、DLABクリア
+            outb(self.base + 2, 0xC7); // FIFO有効・クリア・14バイト閾値
+            outb(self.base + 4, 0x0B); // OUT2（IRQ線）・RTS・DTRをセット
+        }
+    }
+
+    // 送信バッファが空くまで待ってから1バイト送る。
+    fn send(&mut self, byte: u8) {
+        unsafe {
+            // ラインステータスレジスタのbit 5が立つと送信可能
+            while inb(self.base + 5) & 0x20 == 0 {}
+            outb(self.base, byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = {
+        let mut serial_port = SerialPort::new(COM1_BASE);
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+}
+
+/// ホストのシリアルコンソール(COM1)へ出力する。QEMUを`-serial stdio`で
+/// 起動したときにテスト結果などが見えるようになる。
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*));
+    };
+}
+
+/// `serial_print!`に改行を付けた版。
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(concat!($fmt, "\n"), $($arg)*));
+}
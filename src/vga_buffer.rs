@@ -1,4 +1,6 @@
+use core::arch::asm;
 use core::fmt;
+use core::ops::{Deref, DerefMut};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
@@ -28,7 +30,7 @@ pub enum Color {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 // u8は8ビット（1バイト）なので、背景色と前景色をそれぞれ4ビットで表現することができます。これにより、1バイトで2つの色情報を格納できる。
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 // ColorCode::new(Color::Red, Color::Black)は、
 // 背景が黒（0b0000_0000）、前景が赤（0b0000_0001）として組み合わせた
@@ -36,9 +38,18 @@ struct ColorCode(u8);
 impl ColorCode {
     // (background as u8) << 4
     // は、背景色（4ビット）の値を8ビットの変数にキャストしてから、左に4ビットシフト
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    pub fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    // 属性バイトの最上位ビット(bit 7)は、アトリビュートコントローラで
+    // 点滅が有効なら点滅フラグ、無効なら背景の高輝度ビット（背景色 8〜15）
+    // として働く。`blink`が真のときだけビット7を立て、偽のときは`new`と
+    // 同じく背景色の上位ビットをそのまま残す（16色の背景を潰さない）。
+    pub fn with_blink(foreground: Color, background: Color, blink: bool) -> ColorCode {
+        let base = (background as u8) << 4 | (foreground as u8);
+        ColorCode(if blink { base | 0x80 } else { base })
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -51,20 +62,164 @@ struct ScreenChar {
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
+// VGA CRTコントローラのポート。インデックスレジスタ(0x3D4)に
+// レジスタ番号を書き込んでから、データレジスタ(0x3D5)で読み書きする。
+const CRTC_INDEX_PORT: u16 = 0x3D4;
+const CRTC_DATA_PORT: u16 = 0x3D5;
+
+// アトリビュートコントローラ。インデックス/データが同じポート(0x3C0)に
+// 多重化されており、どちらを書くかは内部のフリップフロップで決まる。
+// 入力ステータスレジスタ(0x3DA)を読むとフリップフロップがリセットされる。
+const ATTR_WRITE_PORT: u16 = 0x3C0;
+const ATTR_READ_PORT: u16 = 0x3C1;
+const INPUT_STATUS_PORT: u16 = 0x3DA;
+
+// バイトを指定したI/Oポートへ書き込む。生の`out`命令を使うので
+// 呼び出し側が正しいポートであることを保証しなければならない。
+unsafe fn outb(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+// 指定したI/Oポートから1バイト読み込む。
+unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+// Unicodeの`char`を、ハードウェアのVGAフォントに含まれるコードページ437の
+// バイトへ対応付ける。網羅的ではなく、罫線・網掛け・矢印・記号・よく使う
+// アクセント付き文字など、VGAフォントが実際に持つグリフを中心に拾う。
+// 対応が無ければ`None`を返し、呼び出し側が0xfeへフォールバックする。
+fn char_to_cp437(c: char) -> Option<u8> {
+    let byte = match c {
+        // 矢印
+        '→' => 0x1A,
+        '←' => 0x1B,
+        '↑' => 0x18,
+        '↓' => 0x19,
+        // アクセント付きラテン文字
+        'ü' => 0x81,
+        'é' => 0x82,
+        'â' => 0x83,
+        'ä' => 0x84,
+        'à' => 0x85,
+        'ç' => 0x87,
+        'ê' => 0x88,
+        'è' => 0x8A,
+        'ï' => 0x8B,
+        'ö' => 0x94,
+        'ñ' => 0xA4,
+        'Ñ' => 0xA5,
+        'ß' => 0xE1,
+        'µ' => 0xE6,
+        // 通貨・約物
+        '¢' => 0x9B,
+        '£' => 0x9C,
+        '¥' => 0x9D,
+        'ƒ' => 0x9F,
+        '¡' => 0xAD,
+        '¿' => 0xA8,
+        // 記号
+        '°' => 0xF8,
+        '±' => 0xF1,
+        '÷' => 0xF6,
+        '≈' => 0xF7,
+        '·' => 0xFA,
+        '√' => 0xFB,
+        '²' => 0xFD,
+        // 網掛け・ブロック
+        '░' => 0xB0,
+        '▒' => 0xB1,
+        '▓' => 0xB2,
+        '█' => 0xDB,
+        '▄' => 0xDC,
+        '▌' => 0xDD,
+        '▐' => 0xDE,
+        '▀' => 0xDF,
+        // 単線の罫線
+        '│' => 0xB3,
+        '─' => 0xC4,
+        '┌' => 0xDA,
+        '┐' => 0xBF,
+        '└' => 0xC0,
+        '┘' => 0xD9,
+        '├' => 0xC3,
+        '┤' => 0xB4,
+        '┬' => 0xC2,
+        '┴' => 0xC1,
+        '┼' => 0xC5,
+        // 二重線の罫線
+        '║' => 0xBA,
+        '═' => 0xCD,
+        '╔' => 0xC9,
+        '╗' => 0xBB,
+        '╚' => 0xC8,
+        '╝' => 0xBC,
+        '╠' => 0xCC,
+        '╣' => 0xB9,
+        '╦' => 0xCB,
+        '╩' => 0xCA,
+        '╬' => 0xCE,
+        _ => return None,
+    };
+    Some(byte)
+}
+
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+// 画面からスクロールアウトした行を保持できる行数。
+const HISTORY_LINES: usize = 500;
+
+// スクロールアウトした行を溜めておくリングバッファ。`start`が最古の行を指し、
+// `len`が有効な行数。一杯になると最古の行から上書きする。
+struct History {
+    rows: [[ScreenChar; BUFFER_WIDTH]; HISTORY_LINES],
+    start: usize,
+    len: usize,
+}
+
+impl History {
+    // 1行を追加する。満杯なら最古の行を捨てて`start`を進める。
+    fn push(&mut self, row: [ScreenChar; BUFFER_WIDTH]) {
+        if self.len < HISTORY_LINES {
+            let idx = (self.start + self.len) % HISTORY_LINES;
+            self.rows[idx] = row;
+            self.len += 1;
+        } else {
+            self.rows[self.start] = row;
+            self.start = (self.start + 1) % HISTORY_LINES;
+        }
+    }
+
+    // 論理的な古い順(0が最古)で`i`行目を取り出す。
+    fn get(&self, i: usize) -> &[ScreenChar; BUFFER_WIDTH] {
+        &self.rows[(self.start + i) % HISTORY_LINES]
+    }
+}
+
 pub struct Writer {
     column_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
+    // スクロールアウトした行の履歴
+    history: History,
+    // 最下部（ライブ）からさかのぼって表示している行数。0ならライブ表示。
+    view_offset: usize,
+    // スクロール表示中に退避しておくライブ画面の内容。
+    saved_screen: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
 impl Writer {
     // 単一のバイト（u8）を受け取り、それを適切な位置に書き込む処理
     pub fn write_byte(&mut self, byte: u8) {
+        // 新しい書き込みが来たら表示を最下部へ戻す
+        if self.view_offset != 0 {
+            self.snap_to_bottom();
+        }
         match byte {
             b'\n' => self.new_line(),
             byte => {
@@ -84,24 +239,92 @@ impl Writer {
                 });
                 // 現在の列の位置を1つ進める
                 self.column_position += 1;
+                // ハードウェアカーソルを新しい位置へ移動させる
+                self.update_cursor();
             }
         }
     }
 
-    // 文字列（&str）を受け取り、各バイトを処理するためにwrite_byteメソッドを呼び出し
+    // 物理的なVGAカーソルを現在の書き込み位置（最下行）へ移動させる。
+    // 線形位置 `row * BUFFER_WIDTH + col` をCRTコントローラの
+    // カーソル位置レジスタ(下位0x0F / 上位0x0E)へ書き込む。
+    fn update_cursor(&self) {
+        let row = BUFFER_HEIGHT - 1;
+        let pos = row * BUFFER_WIDTH + self.column_position;
+        unsafe {
+            outb(CRTC_INDEX_PORT, 0x0F);
+            outb(CRTC_DATA_PORT, (pos & 0xFF) as u8);
+            outb(CRTC_INDEX_PORT, 0x0E);
+            outb(CRTC_DATA_PORT, ((pos >> 8) & 0xFF) as u8);
+        }
+    }
+
+    // カーソルを表示し、走査線の開始・終了(0x0A/0x0B)を指定して形を整える。
+    pub fn enable_cursor(&self, cursor_start: u8, cursor_end: u8) {
+        unsafe {
+            // 上位ビット(0x20)を残すとカーソルが消えるのでクリアする
+            outb(CRTC_INDEX_PORT, 0x0A);
+            let current = inb(CRTC_DATA_PORT);
+            outb(CRTC_DATA_PORT, (current & 0xC0) | (cursor_start & 0x1F));
+
+            outb(CRTC_INDEX_PORT, 0x0B);
+            let current = inb(CRTC_DATA_PORT);
+            outb(CRTC_DATA_PORT, (current & 0xE0) | (cursor_end & 0x1F));
+        }
+    }
+
+    // カーソルを非表示にする（0x0Aレジスタのビット5を立てる）。
+    pub fn disable_cursor(&self) {
+        unsafe {
+            outb(CRTC_INDEX_PORT, 0x0A);
+            outb(CRTC_DATA_PORT, 0x20);
+        }
+    }
+
+    // ハードウェアの点滅モードを切り替える。有効なら属性バイトのbit 7は
+    // 点滅フラグになり、無効なら16色目までの背景高輝度ビットとして使える。
+    // モードコントロールレジスタ(インデックス0x10)のbit 3(0x08)で制御する。
+    pub fn set_blink_enabled(&self, enabled: bool) {
+        unsafe {
+            // フリップフロップをインデックス状態にリセットする
+            let _ = inb(INPUT_STATUS_PORT);
+            // bit 5(0x20)を立てたままにしてパレットアドレスを保持しつつ
+            // モードコントロールレジスタ0x10を選ぶ
+            outb(ATTR_WRITE_PORT, 0x10 | 0x20);
+            let mode = inb(ATTR_READ_PORT);
+            let mode = if enabled { mode | 0x08 } else { mode & !0x08 };
+            outb(ATTR_WRITE_PORT, mode);
+            // ビデオ出力を再び有効にする
+            outb(ATTR_WRITE_PORT, 0x20);
+        }
+    }
+
+    // 文字列（&str）をコードページ437へ翻訳しながら書き込む。
+    // ASCIIの印刷可能文字と改行はそのまま、それ以外はUTF-8の`char`として
+    // 解釈し、VGAフォントが持つCP437グリフへ対応付ける。対応が無い文字だけ
+    // 0xfe（塗りつぶし四角）へフォールバックする。
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // 改行文字は改行する
-                // 0x20..=0x7eはASCIIの印刷可能な文字（スペースからチルダまで）
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // それ以外の文字はスペースに置き換える
-                _ => self.write_byte(0xfe),
+        for c in s.chars() {
+            match c {
+                // ASCIIの印刷可能な文字（スペースからチルダまで）と改行は高速経路
+                '\n' => self.write_byte(b'\n'),
+                ' '..='~' => self.write_byte(c as u8),
+                // CP437に存在する文字は対応するバイトへ翻訳する
+                _ => self.write_byte(char_to_cp437(c).unwrap_or(0xfe)),
             }
         }
     }
 
     fn new_line(&mut self) {
+        // 画面外へ押し出される最上行を履歴へ退避する
+        let mut top = [ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        }; BUFFER_WIDTH];
+        for (col, cell) in top.iter_mut().enumerate() {
+            *cell = self.buffer.chars[0][col].read();
+        }
+        self.history.push(top);
         // すべての文字を一行上に持っていき（一番上の行は消去されます）
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
@@ -112,6 +335,8 @@ impl Writer {
         // 前の行の最初から始めるようにカーソルをリセット
         self.clear_row(BUFFER_HEIGHT - 1);
         self.column_position = 0;
+        // 行頭へ戻ったカーソル位置を反映する
+        self.update_cursor();
     }
 
     // すべての文字を空白文字で書き換えることによって行をクリア
@@ -124,6 +349,141 @@ impl Writer {
             self.buffer.chars[row][col].write(blank);
         }
     }
+
+    // 履歴をさかのぼって表示する（ページアップ相当）。初めてライブから離れる
+    // ときに現在の画面を退避し、以降は履歴＋退避画面から可視25行を再描画する。
+    pub fn scroll_up(&mut self, lines: usize) {
+        if self.view_offset == 0 {
+            self.save_screen();
+        }
+        // これ以上は最古の行より上へは行けない
+        self.view_offset = (self.view_offset + lines).min(self.history.len);
+        self.render_view();
+    }
+
+    // 表示を下（新しい方）へ戻す（ページダウン相当）。最下部まで戻ったら
+    // 退避しておいたライブ画面を復元する。
+    pub fn scroll_down(&mut self, lines: usize) {
+        if self.view_offset == 0 {
+            return;
+        }
+        self.view_offset = self.view_offset.saturating_sub(lines);
+        if self.view_offset == 0 {
+            self.restore_screen();
+        } else {
+            self.render_view();
+        }
+    }
+
+    // スクロール表示を解除して最下部（ライブ）へ戻す。
+    fn snap_to_bottom(&mut self) {
+        if self.view_offset != 0 {
+            self.view_offset = 0;
+            self.restore_screen();
+        }
+    }
+
+    // 現在のライブ画面を退避領域へコピーする。
+    fn save_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                self.saved_screen[row][col] = self.buffer.chars[row][col].read();
+            }
+        }
+    }
+
+    // 退避しておいたライブ画面をそのまま書き戻す。
+    fn restore_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                self.buffer.chars[row][col].write(self.saved_screen[row][col]);
+            }
+        }
+    }
+
+    // `view_offset`に応じた可視25行を履歴と退避画面から再描画する。
+    // 論理行は [履歴(古い順) ... 退避画面] の並びで、末尾25行が最下部表示。
+    fn render_view(&mut self) {
+        let total = self.history.len + BUFFER_HEIGHT;
+        let top = total - BUFFER_HEIGHT - self.view_offset;
+        for display_row in 0..BUFFER_HEIGHT {
+            let logical = top + display_row;
+            let row = if logical < self.history.len {
+                *self.history.get(logical)
+            } else {
+                self.saved_screen[logical - self.history.len]
+            };
+            for (col, &cell) in row.iter().enumerate() {
+                self.buffer.chars[display_row][col].write(cell);
+            }
+        }
+    }
+
+    // 指定した絶対座標(row, col)から文字列を書き込む。`column_position`や
+    // カーソル、スクロールバックには触れないので、ステータス行や決まった位置の
+    // 進捗表示に使える。範囲外の起点は無視し、行末を超える分は切り詰める。
+    pub fn write_at(&mut self, row: usize, col: usize, s: &str, color_code: ColorCode) {
+        if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+            return;
+        }
+        let mut c = col;
+        for ch in s.chars() {
+            if c >= BUFFER_WIDTH {
+                break;
+            }
+            let byte = match ch {
+                ' '..='~' => ch as u8,
+                _ => char_to_cp437(ch).unwrap_or(0xfe),
+            };
+            self.buffer.chars[row][c].write(ScreenChar {
+                ascii_character: byte,
+                color_code,
+            });
+            c += 1;
+        }
+    }
+
+    // 現在の色を退避して新しい色に切り替え、ドロップ時に元の色へ戻す
+    // `ColorGuard`を返す。ガードは`Writer`の可変借用を握るので、生きている間は
+    // ガード経由でしか書き込めない。ただし`WRITER`自体の再ロックは型では
+    // 防げず、ガードを保持したまま`WRITER.lock()`を呼ぶとスピンし続ける。
+    pub fn push_color(&mut self, color_code: ColorCode) -> ColorGuard<'_> {
+        let previous = self.color_code;
+        self.color_code = color_code;
+        ColorGuard {
+            writer: self,
+            previous,
+        }
+    }
+}
+
+/// `Writer::push_color`が返すRAIIガード。借用している`Writer`へ`Deref`で
+/// 透過的に書き込め、スコープを抜けると`color_code`を退避しておいた値へ
+/// 復元する。`Writer`の可変借用を保持するが、`WRITER`のロックを握ったまま
+/// 再ロックする使い方はコンパイルでは弾かれず、実行時にデッドロックする。
+pub struct ColorGuard<'a> {
+    writer: &'a mut Writer,
+    previous: ColorCode,
+}
+
+impl Deref for ColorGuard<'_> {
+    type Target = Writer;
+
+    fn deref(&self) -> &Writer {
+        self.writer
+    }
+}
+
+impl DerefMut for ColorGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Writer {
+        self.writer
+    }
+}
+
+impl Drop for ColorGuard<'_> {
+    fn drop(&mut self) {
+        self.writer.color_code = self.previous;
+    }
 }
 
 impl fmt::Write for Writer {
@@ -134,11 +494,24 @@ impl fmt::Write for Writer {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    pub static ref WRITER: Mutex<Writer> = {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: ColorCode::new(Color::Yellow, Color::Black),
+        };
+        Mutex::new(Writer {
+            column_position: 0,
+            color_code: ColorCode::new(Color::Yellow, Color::Black),
+            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+            history: History {
+                rows: [[blank; BUFFER_WIDTH]; HISTORY_LINES],
+                start: 0,
+                len: 0,
+            },
+            view_offset: 0,
+            saved_screen: [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        })
+    };
 }
 
 #[macro_export]
@@ -152,12 +525,34 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+// 前景色・背景色の組を指定して一行出力するマクロ。グローバルな色状態を
+// 汚さずに、色付きの診断やステータス行を出せる。
+#[macro_export]
+macro_rules! cprintln {
+    ($fg:expr, $bg:expr) => {
+        $crate::vga_buffer::_cprint($fg, $bg, format_args!(""))
+    };
+    ($fg:expr, $bg:expr, $($arg:tt)*) => {
+        $crate::vga_buffer::_cprint($fg, $bg, format_args!($($arg)*))
+    };
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     WRITER.lock().write_fmt(args).unwrap();
 }
 
+#[doc(hidden)]
+pub fn _cprint(foreground: Color, background: Color, args: fmt::Arguments) {
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    // ガードがスコープを抜けるときに元の色へ戻るので、グローバルな色は汚れない
+    let mut guard = writer.push_color(ColorCode::new(foreground, background));
+    guard.write_fmt(args).unwrap();
+    guard.write_byte(b'\n');
+}
+
 #[test_case]
 fn test_println_sample() {
     println!("test_println_sample output");
@@ -170,6 +565,54 @@ fn test_println_many() {
     }
 }
 
+#[test_case]
+fn test_cp437_translation() {
+    // 罫線文字がCP437のバイトへ翻訳され、0xfeへ潰されないことを確認する
+    println!("─░°");
+    let expected = [0xC4u8, 0xB0, 0xF8];
+    for (i, &byte) in expected.iter().enumerate() {
+        let screen_char = WRITER.lock().buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        assert_eq!(screen_char.ascii_character, byte);
+    }
+}
+
+#[test_case]
+fn test_scrollback_restores_on_scroll_down() {
+    // 画面を埋めてからスクロールアップし、最下部へ戻したときに
+    // ライブ画面が元通り復元されることを確認する
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    for i in 0..BUFFER_HEIGHT + 5 {
+        writeln!(writer, "line {}", i).unwrap();
+    }
+    let before = writer.buffer.chars[BUFFER_HEIGHT - 1][0].read();
+    writer.scroll_up(3);
+    writer.scroll_down(3);
+    let after = writer.buffer.chars[BUFFER_HEIGHT - 1][0].read();
+    assert_eq!(before.ascii_character, after.ascii_character);
+    assert_eq!(writer.view_offset, 0);
+}
+
+#[test_case]
+fn test_write_at_places_absolutely() {
+    let color = ColorCode::new(Color::White, Color::Blue);
+    WRITER.lock().write_at(0, 5, "Hi", color);
+    let screen = WRITER.lock();
+    assert_eq!(screen.buffer.chars[0][5].read().ascii_character, b'H');
+    assert_eq!(screen.buffer.chars[0][6].read().ascii_character, b'i');
+}
+
+#[test_case]
+fn test_color_guard_restores_on_drop() {
+    let mut writer = WRITER.lock();
+    let before = writer.color_code;
+    {
+        let guard = writer.push_color(ColorCode::new(Color::Red, Color::Black));
+        assert_eq!((*guard).color_code, ColorCode::new(Color::Red, Color::Black));
+    }
+    assert_eq!(writer.color_code, before);
+}
+
 #[test_case]
 fn test_println_output() {
     let s = "Some test string that fits on a single line";